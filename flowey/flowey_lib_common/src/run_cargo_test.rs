@@ -8,6 +8,161 @@ use crate::run_cargo_build::CargoFeatureSet;
 use crate::run_cargo_nextest_run::build_params::TestPackages;
 use flowey::node::prelude::*;
 
+/// The outcome of a single test, as reported by libtest.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TestStatus {
+    Ok,
+    Failed,
+    Ignored,
+}
+
+/// A single test's outcome, parsed out of libtest's `--format=json` event
+/// stream.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct TestOutcome {
+    pub name: String,
+    pub status: TestStatus,
+    /// Wall-clock execution time, in seconds, as reported by
+    /// `--report-time`.
+    pub exec_time: Option<f64>,
+}
+
+/// Structured results of a `cargo test` invocation, parsed from libtest's
+/// per-test JSON events so that downstream flowey nodes can gate on
+/// failures or surface timings without re-parsing human-readable text.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct TestResults {
+    pub outcomes: Vec<TestOutcome>,
+    pub passed: usize,
+    pub failed: usize,
+    pub ignored: usize,
+}
+
+/// Deterministically select the subset of `names` (sorted and deduplicated
+/// first) that belongs to shard `partition_index` out of `partition_count`
+/// total shards.
+///
+/// Partitioning is computed from the sorted name list (not from whatever
+/// order the test binary happens to enumerate tests at runtime), and is a
+/// simple round-robin over that list, so it's reproducible across machines
+/// and the union of every shard is exactly the full input set.
+fn select_partition(
+    mut names: Vec<String>,
+    partition_count: usize,
+    partition_index: usize,
+) -> Vec<String> {
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| i % partition_count == partition_index)
+        .map(|(_, name)| name)
+        .collect()
+}
+
+impl TestResults {
+    /// Parse libtest's `--format=json` event stream, as emitted on stdout by
+    /// `cargo test -- -Z unstable-options --format=json --report-time`.
+    ///
+    /// Lines that aren't a `"type": "test"` event (suite summaries, or plain
+    /// human-readable output if the test binary didn't understand the
+    /// unstable json flags) are silently ignored, so this degrades
+    /// gracefully rather than failing the whole step.
+    fn parse_libtest_json(stdout: &str) -> Self {
+        let mut results = TestResults::default();
+
+        for line in stdout.lines() {
+            let line = line.trim();
+            if !line.starts_with('{') {
+                continue;
+            }
+
+            let Ok(event) = serde_json::from_str::<serde_json::Value>(line) else {
+                continue;
+            };
+
+            if event.get("type").and_then(serde_json::Value::as_str) != Some("test") {
+                continue;
+            }
+
+            let Some(name) = event.get("name").and_then(serde_json::Value::as_str) else {
+                continue;
+            };
+
+            let status = match event.get("event").and_then(serde_json::Value::as_str) {
+                Some("ok") => TestStatus::Ok,
+                Some("failed") => TestStatus::Failed,
+                Some("ignored") => TestStatus::Ignored,
+                // `started` events, etc. don't carry a final outcome
+                _ => continue,
+            };
+
+            let exec_time = event.get("exec_time").and_then(serde_json::Value::as_f64);
+
+            match status {
+                TestStatus::Ok => results.passed += 1,
+                TestStatus::Failed => results.failed += 1,
+                TestStatus::Ignored => results.ignored += 1,
+            }
+
+            results.outcomes.push(TestOutcome {
+                name: name.into(),
+                status,
+                exec_time,
+            });
+        }
+
+        results
+    }
+
+    /// Parse libtest's `--list --format=json` event stream into the flat
+    /// list of test names it discovered.
+    fn parse_libtest_list(stdout: &str) -> Vec<String> {
+        let mut names = Vec::new();
+
+        for line in stdout.lines() {
+            let line = line.trim();
+            if !line.starts_with('{') {
+                continue;
+            }
+
+            let Ok(event) = serde_json::from_str::<serde_json::Value>(line) else {
+                continue;
+            };
+
+            if event.get("type").and_then(serde_json::Value::as_str) != Some("test") {
+                continue;
+            }
+
+            if let Some(name) = event.get("name").and_then(serde_json::Value::as_str) {
+                names.push(name.into());
+            }
+        }
+
+        names
+    }
+
+    /// Parse the plain-text output of stable-toolchain `cargo test --
+    /// --list` (one `<name>: test` / `<name>: benchmark` line per
+    /// discovered test) into the flat list of test names.
+    ///
+    /// Used for the sharding list pre-pass so that `partition_count > 1`
+    /// doesn't, by itself, require a nightly toolchain or `-Z`
+    /// flags — only `structured_output` does.
+    fn parse_libtest_list_plain(stdout: &str) -> Vec<String> {
+        stdout
+            .lines()
+            .filter_map(|line| {
+                let line = line.trim();
+                line.strip_suffix(": test")
+                    .or_else(|| line.strip_suffix(": benchmark"))
+                    .map(str::to_owned)
+            })
+            .collect()
+    }
+}
+
 flowey_request! {
     pub struct Request {
         pub packages: TestPackages,
@@ -15,7 +170,34 @@ flowey_request! {
         pub features: CargoFeatureSet,
         pub target: target_lexicon::Triple,
         pub extra_args: Option<Vec<String>>,
-        pub output: WriteVar<String>,
+        /// Total number of shards the matched test set is split across.
+        /// `1` means no sharding.
+        pub partition_count: usize,
+        /// Which shard (`0..partition_count`) this invocation should run.
+        pub partition_index: usize,
+        /// Run in structured-output mode: pass libtest's unstable
+        /// `--format=json --report-time` flags and populate `results` from
+        /// the parsed event stream. Requires a toolchain that accepts `-Z`
+        /// flags (nightly, or `RUSTC_BOOTSTRAP=1`). Callers that don't need
+        /// per-test outcomes should leave this `false`, in which case
+        /// `results` is written as empty.
+        pub structured_output: bool,
+        /// Structured per-test outcomes, parsed from libtest's JSON event
+        /// stream. Only populated when `structured_output` is set.
+        pub results: WriteVar<TestResults>,
+        /// The raw stdout of the `cargo test` invocation. Kept around for
+        /// callers that haven't migrated to `results` yet.
+        pub output: Option<WriteVar<String>>,
+        /// Don't fail this step when the test run reports a failure; just
+        /// write `results`/`output` and let the caller decide what to do
+        /// with them instead.
+        ///
+        /// Most callers actually run tests and want CI to go red on a real
+        /// failure, so this should be `false`. Set it `true` only when the
+        /// invocation isn't really "running tests" in the pass/fail sense
+        /// (e.g. a `--list-required-artifacts`-style dry run), so a failing
+        /// exit code there doesn't mean what it normally means.
+        pub allow_failures: bool,
     }
 }
 
@@ -40,14 +222,25 @@ impl FlowNode for Node {
             features,
             target,
             extra_args,
+            partition_count,
+            partition_index,
+            structured_output,
+            results,
             output,
+            allow_failures,
         } in requests
         {
+            anyhow::ensure!(
+                partition_count > 0 && partition_index < partition_count,
+                "invalid partition {partition_index}/{partition_count}"
+            );
+
             ctx.req(crate::install_rust::Request::InstallTargetTriple(
                 target.clone(),
             ));
 
             ctx.emit_rust_step("cargo test", |ctx| {
+                let results = results.claim(ctx);
                 let output = output.claim(ctx);
                 let rust_toolchain = rust_toolchain.clone().claim(ctx);
                 let flags = flags.clone().claim(ctx);
@@ -115,13 +308,132 @@ impl FlowNode for Node {
                         cmd = cmd.env("CARGO_INCREMENTAL", "0");
                     }
 
+                    // if sharding across machines, first list the matched tests
+                    // (honoring the same filters the real run will use) so the
+                    // shard can be computed from a stable, sorted name list
+                    // rather than whatever order the test binary enumerates
+                    // tests in at runtime
+                    let shard = if partition_count > 1 {
+                        let mut list_args = args.clone();
+                        list_args.push("--".into());
+                        // only ask for libtest's unstable JSON list format
+                        // when `structured_output` already requires a
+                        // nightly-capable toolchain; otherwise stick to the
+                        // stable, plain-text `--list` output so sharding
+                        // alone doesn't impose that requirement on callers
+                        if structured_output {
+                            list_args.push("-Z".into());
+                            list_args.push("unstable-options".into());
+                            list_args.push("--format=json".into());
+                        }
+                        list_args.push("--list".into());
+                        if let Some(extra_args) = &extra_args {
+                            list_args.extend(extra_args.iter().cloned());
+                        }
+
+                        // listing can't itself "fail" a test, but tolerate a
+                        // non-zero exit the same way the real run does, and
+                        // read stdout regardless
+                        let list_output = cmd.clone().args(list_args).ignore_status().output()?;
+                        let list_stdout = String::from_utf8_lossy(&list_output.stdout);
+                        let names = if structured_output {
+                            TestResults::parse_libtest_list(&list_stdout)
+                        } else {
+                            TestResults::parse_libtest_list_plain(&list_stdout)
+                        };
+
+                        // an empty *full* matched-name list almost always means
+                        // the listing command itself failed (bad flags, crash,
+                        // unsupported toolchain) rather than zero tests
+                        // legitimately matching; treat it as an error so a
+                        // broken listing pass can't report every shard as
+                        // trivially, emptily successful
+                        anyhow::ensure!(
+                            !names.is_empty(),
+                            "listing tests for sharding (partition {partition_index}/{partition_count}) \
+                             found no tests at all; this usually means the listing command failed \
+                             rather than matched zero tests (exit status: {})",
+                            list_output.status
+                        );
+
+                        Some(select_partition(names, partition_count, partition_index))
+                    } else {
+                        None
+                    };
+
+                    // an empty shard means this machine has nothing to run this
+                    // round: `cargo test --exact` with zero trailing names filters
+                    // nothing and would run the *whole* suite, which would mean
+                    // every shard double-running the tests the empty shard was
+                    // supposed to own. Skip the invocation entirely instead.
+                    if matches!(&shard, Some(names) if names.is_empty()) {
+                        rt.write(results, &TestResults::default());
+                        if let Some(output) = output {
+                            rt.write(output, &String::new());
+                        }
+                        return Ok(());
+                    }
+
+                    let mut trailing_args: Vec<String> = Vec::new();
+
+                    if structured_output {
+                        // ask libtest for structured, per-test JSON events instead
+                        // of only human-readable text, so the results can be
+                        // parsed without scraping stdout
+                        trailing_args.push("-Z".into());
+                        trailing_args.push("unstable-options".into());
+                        trailing_args.push("--format=json".into());
+                        trailing_args.push("--report-time".into());
+                    }
+
+                    if let Some(shard) = shard {
+                        // run exactly this shard's tests, explicitly, so the
+                        // union of every shard is exactly the full matched set
+                        trailing_args.push("--exact".into());
+                        trailing_args.extend(shard);
+                    }
+
                     if let Some(extra_args) = extra_args {
+                        trailing_args.extend(extra_args);
+                    }
+
+                    if !trailing_args.is_empty() {
                         args.push("--".into());
-                        args.extend(extra_args);
+                        args.extend(trailing_args);
                     }
 
-                    let stdout_output = cmd.args(args).read()?;
-                    rt.write(output, &stdout_output);
+                    // capture the output ourselves (rather than letting `?`
+                    // abort on a non-zero exit) so that `results`/`output`
+                    // still get written even when the run fails, and this
+                    // step can decide for itself whether that failure should
+                    // propagate
+                    let run_output = cmd.args(args).ignore_status().output()?;
+                    let stdout_output = String::from_utf8_lossy(&run_output.stdout).into_owned();
+
+                    let parsed_results = if structured_output {
+                        TestResults::parse_libtest_json(&stdout_output)
+                    } else {
+                        TestResults::default()
+                    };
+                    rt.write(results, &parsed_results);
+                    if let Some(output) = output {
+                        rt.write(output, &stdout_output);
+                    }
+
+                    if !allow_failures {
+                        if structured_output {
+                            anyhow::ensure!(
+                                parsed_results.failed == 0,
+                                "{} test(s) failed",
+                                parsed_results.failed
+                            );
+                        }
+                        anyhow::ensure!(
+                            run_output.status.success(),
+                            "cargo test exited with {}",
+                            run_output.status
+                        );
+                    }
 
                     Ok(())
                 }
@@ -131,3 +443,68 @@ impl FlowNode for Node {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_partition_covers_every_name_exactly_once() {
+        let names: Vec<String> = (0..7).map(|i| format!("test_{i}")).collect();
+
+        let mut shards = Vec::new();
+        for partition_index in 0..3 {
+            shards.push(select_partition(names.clone(), 3, partition_index));
+        }
+
+        let mut reassembled: Vec<String> = shards.into_iter().flatten().collect();
+        reassembled.sort();
+        let mut expected = names;
+        expected.sort();
+        assert_eq!(reassembled, expected);
+    }
+
+    #[test]
+    fn select_partition_is_stable_regardless_of_input_order() {
+        let sorted = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let shuffled = vec!["c".to_string(), "a".to_string(), "b".to_string()];
+
+        assert_eq!(
+            select_partition(sorted, 2, 0),
+            select_partition(shuffled, 2, 0)
+        );
+    }
+
+    #[test]
+    fn parse_libtest_json_counts_outcomes() {
+        let stdout = concat!(
+            "{\"type\":\"suite\",\"event\":\"started\",\"test_count\":3}\n",
+            "{\"type\":\"test\",\"event\":\"ok\",\"name\":\"a\",\"exec_time\":1.5}\n",
+            "{\"type\":\"test\",\"event\":\"failed\",\"name\":\"b\"}\n",
+            "{\"type\":\"test\",\"event\":\"ignored\",\"name\":\"c\"}\n",
+            "not json at all\n",
+        );
+
+        let results = TestResults::parse_libtest_json(stdout);
+
+        assert_eq!(results.passed, 1);
+        assert_eq!(results.failed, 1);
+        assert_eq!(results.ignored, 1);
+        assert_eq!(results.outcomes.len(), 3);
+        assert_eq!(results.outcomes[0].exec_time, Some(1.5));
+    }
+
+    #[test]
+    fn parse_libtest_list_extracts_names() {
+        let stdout = concat!(
+            "{\"type\":\"test\",\"event\":\"discovered\",\"name\":\"a::b\"}\n",
+            "{\"type\":\"test\",\"event\":\"discovered\",\"name\":\"a::c\"}\n",
+            "{\"type\":\"suite\",\"event\":\"discovered\",\"test_count\":2}\n",
+        );
+
+        assert_eq!(
+            TestResults::parse_libtest_list(stdout),
+            vec!["a::b".to_string(), "a::c".to_string()]
+        );
+    }
+}
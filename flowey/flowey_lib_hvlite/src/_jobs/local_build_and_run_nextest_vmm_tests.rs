@@ -0,0 +1,144 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Job: compute the build selections a VMM nextest archive's tests need,
+//! then run that archive with flaky-test retry and quarantine support.
+//!
+//! This is the thing that actually drives
+//! [`crate::gen_build_selections_for_vmm_tests`] and
+//! [`crate::run_nextest_vmm_tests_with_quarantine`] end to end; neither of
+//! those nodes is wired into the local VMM test flow on its own.
+
+use flowey::node::prelude::*;
+
+/// Which optional build outputs a VMM test run needs, as computed from the
+/// artifacts its tests request.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct BuildSelections {
+    pub openhcl: bool,
+    pub openvmm: bool,
+    pub pipette_windows: bool,
+    pub pipette_linux: bool,
+    pub prep_steps: bool,
+    pub guest_test_uefi: bool,
+    pub tmks: bool,
+    pub tmk_vmm_windows: bool,
+    pub tmk_vmm_linux: bool,
+    pub vmgstool: bool,
+}
+
+flowey_request! {
+    pub struct Request {
+        pub archive_file: ReadVar<PathBuf>,
+        pub target: target_lexicon::Triple,
+        pub nextest_bin: ReadVar<PathBuf>,
+        pub working_dir: ReadVar<PathBuf>,
+        pub config_file: ReadVar<PathBuf>,
+        pub nextest_profile: String,
+        pub nextest_filter_expr: String,
+        pub output_dir: ReadVar<PathBuf>,
+        pub release: bool,
+        /// Test-name glob patterns for known-flaky tests: always run, but
+        /// never fail the job regardless of outcome.
+        pub quarantine: Vec<String>,
+        /// How many additional attempts a failing, non-quarantined test
+        /// gets before it's counted as a real failure.
+        pub retries: u32,
+        /// The build outputs computed to be required by this run's tests.
+        pub build_selections: WriteVar<BuildSelections>,
+        /// The retry/quarantine-aware run summary.
+        pub summary: WriteVar<crate::run_nextest_vmm_tests_with_quarantine::TestRunSummary>,
+    }
+}
+
+new_simple_flow_node!(struct Node);
+
+impl SimpleFlowNode for Node {
+    type Request = Request;
+
+    fn imports(ctx: &mut ImportCtx<'_>) {
+        ctx.import::<crate::gen_build_selections_for_vmm_tests::Node>();
+        ctx.import::<crate::run_nextest_vmm_tests_with_quarantine::Node>();
+    }
+
+    fn process_request(request: Self::Request, ctx: &mut NodeCtx<'_>) -> anyhow::Result<()> {
+        let Request {
+            archive_file,
+            target,
+            nextest_bin,
+            working_dir,
+            config_file,
+            nextest_profile,
+            nextest_filter_expr,
+            output_dir,
+            release,
+            quarantine,
+            retries,
+            build_selections,
+            summary,
+        } = request;
+
+        ctx.req(crate::gen_build_selections_for_vmm_tests::Request {
+            archive_file: archive_file.clone(),
+            target: target.clone(),
+            nextest_bin: nextest_bin.clone(),
+            working_dir: working_dir.clone(),
+            config_file: config_file.clone(),
+            nextest_profile: nextest_profile.clone(),
+            nextest_filter_expr: nextest_filter_expr.clone(),
+            output_dir: output_dir.clone(),
+            release,
+            build_selections,
+        });
+
+        let test_run_summary =
+            ctx.reqv(
+                |v| crate::run_nextest_vmm_tests_with_quarantine::Request {
+                    nextest_bin,
+                    archive_file,
+                    target,
+                    working_dir,
+                    config_file,
+                    nextest_profile,
+                    nextest_filter_expr,
+                    output_dir,
+                    quarantine,
+                    retries,
+                    summary: v,
+                },
+            );
+
+        ctx.emit_rust_step("gate the job on the vmm test run's outcome", |ctx| {
+            let test_run_summary = test_run_summary.claim(ctx);
+            let summary = summary.claim(ctx);
+
+            move |rt| {
+                let test_run_summary = rt.read(test_run_summary);
+
+                log::info!(
+                    "vmm test run: {} passed, {} flaky, {} failed, {} quarantined",
+                    test_run_summary.passed,
+                    test_run_summary.flaky,
+                    test_run_summary.failed,
+                    test_run_summary.quarantined
+                );
+
+                rt.write(summary, &test_run_summary);
+
+                // flaky (retried-to-pass) and quarantined tests never fail
+                // the job - that's the whole point of this node - but a
+                // test that's still failing after its retry budget is a
+                // real failure
+                anyhow::ensure!(
+                    test_run_summary.failed == 0,
+                    "{} vmm test(s) failed after retries",
+                    test_run_summary.failed
+                );
+
+                Ok(())
+            }
+        });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,7 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Top-level jobs: the nodes that wire together the individual build/test
+//! steps that live as siblings of this module.
+
+pub mod local_build_and_run_nextest_vmm_tests;
@@ -1,11 +1,117 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+use crate::_jobs::local_build_and_run_nextest_vmm_tests::BuildSelections;
 use anyhow::Ok;
 use anyhow::anyhow;
 use flowey::node::prelude::*;
+use petri_artifacts_common::artifacts as common;
+use petri_artifacts_vmm_test::artifacts::*;
 use serde_json::Value;
 
+/// A single entry in [`ARTIFACT_BUILD_RULES`]: an artifact a test might
+/// request, and what to flip on in [`BuildSelections`] to produce it.
+type ArtifactBuildRule = (petri_artifacts_core::ErasedArtifactHandle, fn(&mut BuildSelections));
+
+fn apply_tmk_vmm_native(b: &mut BuildSelections) {
+    b.tmks = true;
+    // TMK_VMM_NATIVE could be windows or linux depending on host
+    // xtask-fmt allow-target-os oneoff-petri-native-test-deps
+    #[cfg(target_os = "windows")]
+    {
+        b.tmk_vmm_windows = true;
+    }
+    // xtask-fmt allow-target-os oneoff-petri-native-test-deps
+    #[cfg(target_os = "linux")]
+    {
+        b.tmk_vmm_linux = true;
+    }
+}
+
+/// Declarative mapping from artifacts a VMM test can request onto the
+/// [`BuildSelections`] fields that need to be turned on to produce them.
+///
+/// New IGVM flavors or TMK variants should add an entry here, next to the
+/// rest of the mapping, rather than growing a hand-maintained if-chain.
+/// Artifacts with no matching entry are reported by
+/// `apply_artifact_build_rule` instead of silently leaving
+/// `BuildSelections` under-populated.
+const ARTIFACT_BUILD_RULES: &[ArtifactBuildRule] = &[
+    (common::PIPETTE_WINDOWS_X64, |b| b.pipette_windows = true),
+    (common::PIPETTE_WINDOWS_AARCH64, |b| b.pipette_windows = true),
+    (common::PIPETTE_LINUX_X64, |b| b.pipette_linux = true),
+    (common::PIPETTE_LINUX_AARCH64, |b| b.pipette_linux = true),
+    (OPENVMM_NATIVE, |b| b.openvmm = true),
+    (openhcl_igvm::LATEST_STANDARD_X64, |b| b.openhcl = true),
+    (openhcl_igvm::LATEST_STANDARD_DEV_KERNEL_X64, |b| {
+        b.openhcl = true
+    }),
+    (openhcl_igvm::LATEST_CVM_X64, |b| b.openhcl = true),
+    (openhcl_igvm::LATEST_LINUX_DIRECT_TEST_X64, |b| {
+        b.openhcl = true
+    }),
+    (openhcl_igvm::LATEST_STANDARD_AARCH64, |b| b.openhcl = true),
+    (openhcl_igvm::LATEST_STANDARD_DEV_KERNEL_AARCH64, |b| {
+        b.openhcl = true
+    }),
+    (openhcl_igvm::RELEASE_25_05_STANDARD_X64, |b| {
+        b.openhcl = true
+    }),
+    (openhcl_igvm::RELEASE_25_05_LINUX_DIRECT_X64, |b| {
+        b.openhcl = true
+    }),
+    (openhcl_igvm::RELEASE_25_05_STANDARD_AARCH64, |b| {
+        b.openhcl = true
+    }),
+    (openhcl_igvm::um_bin::LATEST_LINUX_DIRECT_TEST_X64, |b| {
+        b.openhcl = true
+    }),
+    (openhcl_igvm::um_dbg::LATEST_LINUX_DIRECT_TEST_X64, |b| {
+        b.openhcl = true
+    }),
+    (test_vhd::GUEST_TEST_UEFI_X64, |b| b.guest_test_uefi = true),
+    (test_vhd::GUEST_TEST_UEFI_AARCH64, |b| {
+        b.guest_test_uefi = true
+    }),
+    (
+        test_vhd::GEN2_WINDOWS_DATA_CENTER_CORE2025_X64_PREPPED,
+        |b| b.prep_steps = true,
+    ),
+    (tmks::TMK_VMM_NATIVE, apply_tmk_vmm_native),
+    (tmks::TMK_VMM_LINUX_X64_MUSL, |b| {
+        b.tmks = true;
+        b.tmk_vmm_linux = true;
+    }),
+    (tmks::TMK_VMM_LINUX_AARCH64_MUSL, |b| {
+        b.tmks = true;
+        b.tmk_vmm_linux = true;
+    }),
+    (tmks::SIMPLE_TMK_X64, |b| b.tmks = true),
+    (tmks::SIMPLE_TMK_AARCH64, |b| b.tmks = true),
+    (VMGSTOOL_NATIVE, |b| b.vmgstool = true),
+];
+
+/// Apply every registered rule that matches `id`, and log a structured
+/// warning if none do — i.e. some test requires an artifact that nothing
+/// in this registry knows how to build, which would otherwise produce a
+/// build that's silently missing a dependency at runtime.
+fn apply_artifact_build_rule(
+    id: petri_artifacts_core::ErasedArtifactHandle,
+    computed_build: &mut BuildSelections,
+) {
+    let mut matched = false;
+    for (_, apply) in ARTIFACT_BUILD_RULES.iter().filter(|(handle, _)| *handle == id) {
+        apply(computed_build);
+        matched = true;
+    }
+    if !matched {
+        log::warn!(
+            "artifact {id:?} was requested by a test but has no registered build selection rule; \
+             the resulting build may be missing a dependency"
+        );
+    }
+}
+
 flowey_request! {
     pub struct Request {
         pub archive_file: ReadVar<PathBuf>,
@@ -62,6 +168,10 @@ impl SimpleFlowNode for Node {
             output_file: v,
         });
 
+        // we only care about the raw `--list-required-artifacts=json` output here,
+        // not the per-test pass/fail results, so the structured `results` var is
+        // left unread
+        let (test_results_write, _test_results_read) = ctx.new_var();
         let test_artifact_requirements = ctx.reqv(|v| flowey_lib_common::run_cargo_test::Request {
             packages:
                 flowey_lib_common::run_cargo_nextest_run::build_params::TestPackages::Crates {
@@ -74,16 +184,25 @@ impl SimpleFlowNode for Node {
             features: Default::default(),
             target,
             extra_args: Some(vec!["--list-required-artifacts=json".into()]),
-            output: v,
+            partition_count: 1,
+            partition_index: 0,
+            structured_output: false,
+            results: test_results_write,
+            output: Some(v),
+            // `--list-required-artifacts=json` makes the test binary print
+            // its artifact requirements instead of actually running tests,
+            // so its exit code doesn't carry the usual pass/fail meaning.
+            allow_failures: true,
         });
 
         // Analyze artifact requirements to determine what needs to be built
         // This happens after building the test binary but before building artifacts
-        let computed_build_selections = ctx.emit_rust_stepv(
+        ctx.emit_rust_step(
             "analyze artifact requirements and determine build selections",
             |ctx| {
                 let nextest_list_cmd = nextest_list_cmd.claim(ctx);
                 let test_artifact_requirements = test_artifact_requirements.claim(ctx);
+                let build_selections = build_selections.claim(ctx);
                 let nextest_filter_expr = nextest_filter_expr.clone();
 
                 move |rt| {
@@ -162,10 +281,10 @@ impl SimpleFlowNode for Node {
                         all_optional_artifacts
                     );
 
-                    // Determine what needs to be built based on the artifact requirements
-                    use petri_artifacts_common::artifacts as common;
-                    use petri_artifacts_vmm_test::artifacts::*;
-
+                    // Determine what needs to be built based on the artifact
+                    // requirements, via the declarative registry above. Every
+                    // field starts disabled; each matched artifact turns on
+                    // whatever it needs.
                     let mut computed_build = BuildSelections::default();
 
                     // Start with everything disabled
@@ -187,76 +306,14 @@ impl SimpleFlowNode for Node {
                         .collect();
 
                     for id in all_artifacts {
-                        // Pipette artifacts
-                        if id == common::PIPETTE_WINDOWS_X64 || id == common::PIPETTE_WINDOWS_AARCH64 {
-                            computed_build.pipette_windows = true;
-                        }
-                        if id == common::PIPETTE_LINUX_X64 || id == common::PIPETTE_LINUX_AARCH64 {
-                            computed_build.pipette_linux = true;
-                        }
-
-                        // OpenVMM native executable
-                        if id == OPENVMM_NATIVE {
-                            computed_build.openvmm = true;
-                        }
-
-                        // OpenHCL IGVM artifacts
-                        if id == openhcl_igvm::LATEST_STANDARD_X64
-                            || id == openhcl_igvm::LATEST_STANDARD_DEV_KERNEL_X64
-                            || id == openhcl_igvm::LATEST_CVM_X64
-                            || id == openhcl_igvm::LATEST_LINUX_DIRECT_TEST_X64
-                            || id == openhcl_igvm::LATEST_STANDARD_AARCH64
-                            || id == openhcl_igvm::LATEST_STANDARD_DEV_KERNEL_AARCH64
-                            || id == openhcl_igvm::RELEASE_25_05_STANDARD_X64
-                            || id == openhcl_igvm::RELEASE_25_05_LINUX_DIRECT_X64
-                            || id == openhcl_igvm::RELEASE_25_05_STANDARD_AARCH64
-                            || id == openhcl_igvm::um_bin::LATEST_LINUX_DIRECT_TEST_X64
-                            || id == openhcl_igvm::um_dbg::LATEST_LINUX_DIRECT_TEST_X64 {
-                            computed_build.openhcl = true;
-                        }
-
-                        // Guest test UEFI disk
-                        if id == test_vhd::GUEST_TEST_UEFI_X64 || id == test_vhd::GUEST_TEST_UEFI_AARCH64 {
-                            computed_build.guest_test_uefi = true;
-                        }
-
-                        // Prepped test artifacts require prep steps
-                        if id == test_vhd::GEN2_WINDOWS_DATA_CENTER_CORE2025_X64_PREPPED {
-                            computed_build.prep_steps = true;
-                        }
-
-                        // TMK artifacts
-                        if id == tmks::TMK_VMM_NATIVE {
-                            computed_build.tmks = true;
-                            // TMK_VMM_NATIVE could be windows or linux depending on host
-                            // xtask-fmt allow-target-os oneoff-petri-native-test-deps
-                            #[cfg(target_os = "windows")]
-                            {
-                                computed_build.tmk_vmm_windows = true;
-                            }
-                            // xtask-fmt allow-target-os oneoff-petri-native-test-deps
-                            #[cfg(target_os = "linux")]
-                            {
-                                computed_build.tmk_vmm_linux = true;
-                            }
-                        }
-                        if id == tmks::TMK_VMM_LINUX_X64_MUSL || id == tmks::TMK_VMM_LINUX_AARCH64_MUSL {
-                            computed_build.tmks = true;
-                            computed_build.tmk_vmm_linux = true;
-                        }
-                        if id == tmks::SIMPLE_TMK_X64 || id == tmks::SIMPLE_TMK_AARCH64 {
-                            computed_build.tmks = true;
-                        }
-
-                        // Vmgstool
-                        if id == VMGSTOOL_NATIVE {
-                            computed_build.vmgstool = true;
-                        }
+                        apply_artifact_build_rule(id, &mut computed_build);
                     }
 
                     log::info!("Computed build selections based on artifacts: {:#?}", computed_build);
 
-                    Ok(computed_build)
+                    rt.write(build_selections, &computed_build);
+
+                    Ok(())
                 }
             },
         );
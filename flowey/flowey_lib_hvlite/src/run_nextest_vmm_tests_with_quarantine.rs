@@ -0,0 +1,492 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Run a VMM nextest archive with flaky-test retry and quarantine support.
+//!
+//! Hardware-dependent VMM tests are occasionally flaky in ways that have
+//! nothing to do with the change under test. This node lets a caller mark
+//! known-flaky tests as quarantined (always run, never block the job) and
+//! gives every other failing test a retry budget before it's counted as a
+//! real failure, so that noisy tests don't block merges while still
+//! surfacing that they're unstable.
+
+use anyhow::Context;
+use flowey::node::prelude::*;
+
+/// The final disposition of a single test across all of its attempts.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestDisposition {
+    /// Passed on the first attempt.
+    Passed,
+    /// Failed at least once, but eventually passed within the retry budget.
+    Flaky,
+    /// Never passed within the retry budget.
+    Failed,
+    /// Matched a quarantine glob: always run, but never fails the job.
+    Quarantined,
+}
+
+/// A single attempt at running one test.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct TestAttempt {
+    pub name: String,
+    /// 0-indexed attempt number.
+    pub attempt: u32,
+    pub passed: bool,
+    pub duration_secs: Option<f64>,
+}
+
+/// Final disposition of a single test, after all retries.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct TestResult {
+    pub name: String,
+    pub disposition: TestDisposition,
+    pub attempts: u32,
+}
+
+/// Machine-readable summary of a retry-with-quarantine nextest run,
+/// persisted to `output_dir` so that flakiness can be tracked over time.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct TestRunSummary {
+    pub passed: usize,
+    pub flaky: usize,
+    pub failed: usize,
+    pub quarantined: usize,
+    pub results: Vec<TestResult>,
+    pub attempts: Vec<TestAttempt>,
+}
+
+flowey_request! {
+    pub struct Request {
+        pub nextest_bin: ReadVar<PathBuf>,
+        pub archive_file: ReadVar<PathBuf>,
+        pub target: target_lexicon::Triple,
+        pub working_dir: ReadVar<PathBuf>,
+        pub config_file: ReadVar<PathBuf>,
+        pub nextest_profile: String,
+        pub nextest_filter_expr: String,
+        pub output_dir: ReadVar<PathBuf>,
+        /// Test-name glob patterns (`*` wildcards) for known-flaky tests:
+        /// always run, but never fail the overall job regardless of
+        /// outcome.
+        pub quarantine: Vec<String>,
+        /// How many additional attempts a failing, non-quarantined test
+        /// gets before it's counted as a real failure.
+        pub retries: u32,
+        pub summary: WriteVar<TestRunSummary>,
+    }
+}
+
+new_simple_flow_node!(struct Node);
+
+impl SimpleFlowNode for Node {
+    type Request = Request;
+
+    fn imports(ctx: &mut ImportCtx<'_>) {
+        ctx.import::<flowey_lib_common::run_cargo_nextest_list::Node>();
+        ctx.import::<flowey_lib_common::install_rust::Node>();
+    }
+
+    fn process_request(request: Self::Request, ctx: &mut NodeCtx<'_>) -> anyhow::Result<()> {
+        let Request {
+            nextest_bin,
+            archive_file,
+            target,
+            working_dir,
+            config_file,
+            nextest_profile,
+            nextest_filter_expr,
+            output_dir,
+            quarantine,
+            retries,
+            summary,
+        } = request;
+
+        let nextest_list_cmd = ctx.reqv(|v| flowey_lib_common::run_cargo_nextest_list::Request {
+            run_kind: flowey_lib_common::run_cargo_nextest_run::NextestRunKind::RunFromArchive {
+                archive_file: archive_file.clone(),
+                target: Some(ReadVar::from_static(target.clone())),
+                nextest_bin: Some(nextest_bin.clone()),
+            },
+            working_dir: working_dir.clone(),
+            config_file: config_file.clone(),
+            nextest_profile: nextest_profile.clone(),
+            nextest_filter_expr: Some(nextest_filter_expr),
+            run_ignored: false,
+            extra_env: None,
+            output_dir: output_dir.clone(),
+            pre_run_deps: vec![],
+            output_file: v,
+        });
+
+        ctx.emit_rust_step("run vmm tests with retry and quarantine", |ctx| {
+            let nextest_bin = nextest_bin.claim(ctx);
+            let archive_file = archive_file.claim(ctx);
+            let working_dir = working_dir.claim(ctx);
+            let config_file = config_file.claim(ctx);
+            let output_dir = output_dir.claim(ctx);
+            let nextest_list_cmd = nextest_list_cmd.claim(ctx);
+            let summary = summary.claim(ctx);
+
+            move |rt| {
+                let nextest_bin = rt.read(nextest_bin);
+                let archive_file = rt.read(archive_file);
+                let working_dir = rt.read(working_dir);
+                let config_file = rt.read(config_file);
+                let output_dir = rt.read(output_dir);
+                let nextest_list_path = rt.read(nextest_list_cmd);
+
+                let matched_names =
+                    parse_matched_test_names(&fs_err::read(&nextest_list_path)?)?;
+
+                let (quarantined, non_quarantined): (Vec<_>, Vec<_>) = matched_names
+                    .into_iter()
+                    .partition(|name| quarantine.iter().any(|glob| glob_match(glob, name)));
+
+                let mut summary_out = TestRunSummary::default();
+
+                // Track which non-quarantined tests still need to be (re)run, and
+                // how many attempts each one has had so far.
+                let mut pending = non_quarantined;
+                let mut attempts_so_far: std::collections::HashMap<String, u32> =
+                    Default::default();
+                let mut passed_after_retry: std::collections::HashSet<String> = Default::default();
+
+                for attempt in 0..=retries {
+                    if pending.is_empty() {
+                        break;
+                    }
+
+                    let outcomes = run_nextest_subset(
+                        &nextest_bin,
+                        &archive_file,
+                        &target,
+                        &working_dir,
+                        &config_file,
+                        &nextest_profile,
+                        &pending,
+                        attempt,
+                        &mut summary_out.attempts,
+                    )?;
+
+                    pending = Vec::new();
+                    for (name, passed) in outcomes {
+                        *attempts_so_far.entry(name.clone()).or_insert(0) += 1;
+                        if passed {
+                            if attempt > 0 {
+                                passed_after_retry.insert(name);
+                            }
+                        } else {
+                            pending.push(name);
+                        }
+                    }
+                }
+
+                for (name, attempt_count) in &attempts_so_far {
+                    let still_failing = pending.contains(name);
+                    let disposition = if still_failing {
+                        TestDisposition::Failed
+                    } else if passed_after_retry.contains(name) {
+                        TestDisposition::Flaky
+                    } else {
+                        TestDisposition::Passed
+                    };
+
+                    match disposition {
+                        TestDisposition::Passed => summary_out.passed += 1,
+                        TestDisposition::Flaky => summary_out.flaky += 1,
+                        TestDisposition::Failed => summary_out.failed += 1,
+                        TestDisposition::Quarantined => unreachable!(),
+                    }
+
+                    summary_out.results.push(TestResult {
+                        name: name.clone(),
+                        disposition,
+                        attempts: *attempt_count,
+                    });
+                }
+
+                // Quarantined tests are always run, exactly once (no retries -
+                // they're expected to be flaky), but their outcome never fails
+                // the job.
+                if !quarantined.is_empty() {
+                    let outcomes = run_nextest_subset(
+                        &nextest_bin,
+                        &archive_file,
+                        &target,
+                        &working_dir,
+                        &config_file,
+                        &nextest_profile,
+                        &quarantined,
+                        0,
+                        &mut summary_out.attempts,
+                    )?;
+
+                    for (name, _passed) in outcomes {
+                        summary_out.quarantined += 1;
+                        summary_out.results.push(TestResult {
+                            name,
+                            disposition: TestDisposition::Quarantined,
+                            attempts: 1,
+                        });
+                    }
+                }
+
+                log::info!(
+                    "vmm test run: {} passed, {} flaky, {} failed, {} quarantined",
+                    summary_out.passed,
+                    summary_out.flaky,
+                    summary_out.failed,
+                    summary_out.quarantined
+                );
+
+                fs_err::create_dir_all(&output_dir)?;
+                let summary_path = output_dir.join("test-run-summary.json");
+                fs_err::write(&summary_path, serde_json::to_vec_pretty(&summary_out)?)
+                    .with_context(|| format!("writing {}", summary_path.display()))?;
+
+                rt.write(summary, &summary_out);
+
+                Ok(())
+            }
+        });
+
+        Ok(())
+    }
+}
+
+/// Parse the `rust-suites` object out of a `cargo nextest list
+/// --message-format json` report into the flat list of test names whose
+/// filter matched.
+fn parse_matched_test_names(nextest_list_output: &[u8]) -> anyhow::Result<Vec<String>> {
+    let v: serde_json::Value = serde_json::from_slice(nextest_list_output)?;
+    let rust_suites = v
+        .get("rust-suites")
+        .and_then(serde_json::Value::as_object)
+        .context("missing rust-suites")?;
+
+    let mut matched_names = Vec::new();
+    for (_suite_name, suite_val) in rust_suites {
+        if let Some(testcases) = suite_val.get("testcases").and_then(serde_json::Value::as_object)
+        {
+            for (test_name, test_val) in testcases {
+                let status = test_val
+                    .get("filter-match")
+                    .and_then(|fm| fm.get("status"))
+                    .and_then(serde_json::Value::as_str);
+                if status == Some("matches") {
+                    matched_names.push(test_name.clone());
+                }
+            }
+        }
+    }
+
+    Ok(matched_names)
+}
+
+/// Run exactly `names` from the nextest archive, recording one
+/// [`TestAttempt`] per test into `attempts`, and return each test's
+/// pass/fail outcome.
+#[allow(clippy::too_many_arguments)]
+fn run_nextest_subset(
+    nextest_bin: &std::path::Path,
+    archive_file: &std::path::Path,
+    target: &target_lexicon::Triple,
+    working_dir: &std::path::Path,
+    config_file: &std::path::Path,
+    nextest_profile: &str,
+    names: &[String],
+    attempt: u32,
+    attempts: &mut Vec<TestAttempt>,
+) -> anyhow::Result<Vec<(String, bool)>> {
+    // Build an exact-match filter expression covering just this subset, so
+    // that each retry pass only re-runs the tests that are still pending.
+    let filter_expr = names
+        .iter()
+        .map(|name| format!("test(={name})"))
+        .collect::<Vec<_>>()
+        .join(" | ");
+
+    let sh = xshell::Shell::new()?;
+    let archive_file = archive_file.display().to_string();
+    let config_file = config_file.display().to_string();
+    let target = target.to_string();
+
+    // `--no-fail-fast` so one failing test doesn't abort the rest of the
+    // subset; outcomes are parsed from nextest's libtest-json message
+    // format, the same per-test event shape `cargo test` emits. That format
+    // is still experimental and is rejected unless the caller opts in.
+    let cmd = xshell::cmd!(
+        sh,
+        "{nextest_bin} run
+            --archive-file {archive_file}
+            --target {target}
+            --workspace-remap {working_dir}
+            --config-file {config_file}
+            --nextest-profile {nextest_profile}
+            --no-fail-fast
+            --message-format libtest-json
+            -E {filter_expr}"
+    )
+    .env("NEXTEST_EXPERIMENTAL_LIBTEST_JSON", "1");
+
+    // a failing test makes `cargo nextest run` exit non-zero - that's
+    // expected here (it's the whole point of `--no-fail-fast` plus the
+    // retry loop above), so capture output regardless of exit status
+    // instead of erroring out the step on the first failure
+    let output = cmd.ignore_status().output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    let mut seen = std::collections::HashSet::new();
+    let mut outcomes = Vec::new();
+    for line in stdout.lines() {
+        let line = line.trim();
+        if !line.starts_with('{') {
+            continue;
+        }
+        let Ok(event) = serde_json::from_str::<serde_json::Value>(line) else {
+            continue;
+        };
+        if event.get("type").and_then(serde_json::Value::as_str) != Some("test") {
+            continue;
+        }
+        let Some(name) = event.get("name").and_then(serde_json::Value::as_str) else {
+            continue;
+        };
+        let passed = match event.get("event").and_then(serde_json::Value::as_str) {
+            Some("ok") => true,
+            Some("failed") => false,
+            // `started`, `ignored`, etc: not a final outcome
+            _ => continue,
+        };
+        let duration_secs = event.get("exec_time").and_then(serde_json::Value::as_f64);
+
+        seen.insert(name.to_owned());
+        attempts.push(TestAttempt {
+            name: name.to_owned(),
+            attempt,
+            passed,
+            duration_secs,
+        });
+        outcomes.push((name.to_owned(), passed));
+    }
+
+    // if nextest didn't report a single test outcome and exited non-zero,
+    // that's most likely an invocation/config problem (bad --archive-file,
+    // target mismatch, crashing nextest_bin) rather than per-test failures -
+    // surface stderr so it isn't silently indistinguishable from a wall of
+    // real test failures
+    if seen.is_empty() && !names.is_empty() && !output.status.success() {
+        log::warn!(
+            "cargo-nextest exited with {} before reporting any test outcomes for this attempt; \
+             this usually means the invocation itself failed, not the tests. stderr:\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    // a test that never reports a recognizable final event for this attempt
+    // (the harness crashed, got killed for hanging, or emitted something
+    // this parser doesn't understand) must still come back as a failure,
+    // not silently vanish from the summary
+    for name in names {
+        if seen.contains(name) {
+            continue;
+        }
+        attempts.push(TestAttempt {
+            name: name.clone(),
+            attempt,
+            passed: false,
+            duration_secs: None,
+        });
+        outcomes.push((name.clone(), false));
+    }
+
+    Ok(outcomes)
+}
+
+/// Match `name` against a glob pattern supporting any number of `*`
+/// wildcards (e.g. `vmm_tests::*::flaky_*`), or an exact string if there's
+/// no `*` at all. Each `*` matches zero or more characters; segments
+/// between wildcards must appear in order.
+fn glob_match(glob: &str, name: &str) -> bool {
+    let segments: Vec<&str> = glob.split('*').collect();
+    if segments.len() == 1 {
+        return glob == name;
+    }
+
+    let mut pos = 0;
+
+    // the first segment anchors the start, unless the glob itself starts
+    // with `*`
+    let first = segments[0];
+    if !first.is_empty() {
+        if !name[pos..].starts_with(first) {
+            return false;
+        }
+        pos += first.len();
+    }
+
+    // interior segments must each be found, in order, somewhere after the
+    // end of the previous match
+    for segment in &segments[1..segments.len() - 1] {
+        if segment.is_empty() {
+            continue;
+        }
+        match name[pos..].find(segment) {
+            Some(idx) => pos += idx + segment.len(),
+            None => return false,
+        }
+    }
+
+    // the last segment anchors the end, unless the glob itself ends with
+    // `*`
+    let last = segments[segments.len() - 1];
+    if last.is_empty() {
+        true
+    } else {
+        name[pos..].ends_with(last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_match_exact() {
+        assert!(glob_match("vmm_tests::foo", "vmm_tests::foo"));
+        assert!(!glob_match("vmm_tests::foo", "vmm_tests::bar"));
+    }
+
+    #[test]
+    fn glob_match_single_wildcard() {
+        assert!(glob_match("vmm_tests::*", "vmm_tests::foo"));
+        assert!(glob_match("*::flaky_test", "vmm_tests::flaky_test"));
+        assert!(glob_match("*", "anything"));
+        assert!(!glob_match("vmm_tests::*", "other::foo"));
+    }
+
+    #[test]
+    fn glob_match_multiple_wildcards() {
+        assert!(glob_match(
+            "vmm_tests::*::flaky_*",
+            "vmm_tests::openvmm::flaky_boot"
+        ));
+        assert!(!glob_match(
+            "vmm_tests::*::flaky_*",
+            "vmm_tests::openvmm::stable_boot"
+        ));
+        assert!(!glob_match(
+            "vmm_tests::*::flaky_*",
+            "other::openvmm::flaky_boot"
+        ));
+    }
+
+    #[test]
+    fn glob_match_requires_in_order_segments() {
+        // `b` must appear after `a`'s match, not just anywhere in the name
+        assert!(!glob_match("*a*b*", "ba"));
+        assert!(glob_match("*a*b*", "ab"));
+    }
+}